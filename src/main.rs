@@ -2,17 +2,38 @@ extern crate mio;
 extern crate bytes;
 
 use bytes::Take;
-use mio::{EventLoop, EventSet, Handler, PollOpt, Token, TryRead, TryWrite};
+use mio::{EventLoop, EventSet, Handler, PollOpt, Timeout, Token, TryRead, TryWrite};
 use mio::tcp::{Shutdown, TcpListener, TcpStream};
 use mio::util::Slab;
+use std::collections::HashMap;
 use std::io::{BufWriter, Cursor, Write};
 
 const SERVER: Token = Token(0);
 
+// How long a connection may sit in `State::Reading` with no activity
+// before it's killed with a 408 (an h2 connection is closed without one).
+// The timer restarts on every event while reading, so a client that keeps
+// sending bytes more often than this is not cut off.
+const REQUEST_TIMEOUT_MS: u64 = 10_000;
+
+// The preamble every HTTP/2 connection opens with, in lieu of an
+// Upgrade handshake (h2 over cleartext, "h2c"). Seeing this instead of
+// an HTTP/1 request line is how we tell the two protocols apart.
+const HTTP2_PREFACE: &'static [u8] = b"PRI * HTTP/2.0\r\n\r\nSM\r\n\r\n";
+
+// Upper bound on the combined request-line + header bytes buffered before
+// the body starts. Without this, a client that never sends a blank line
+// can grow `self.req` without limit; once exceeded we fail the request
+// with `BadRequest` instead of continuing to buffer forever.
+const MAX_HEADER_BYTES: usize = 8192;
+
 #[derive(Debug)]
 enum StatusCode {
     Ok,
     NotFound,
+    MethodNotAllowed,
+    BadRequest,
+    RequestTimeout,
     Error,
 }
 
@@ -23,6 +44,9 @@ impl StatusCode {
         size += try!(match *self {
             StatusCode::Ok => buf.write(b"200 OK"),
             StatusCode::NotFound => buf.write(b"404 Not Found"),
+            StatusCode::MethodNotAllowed => buf.write(b"405 Method Not Allowed"),
+            StatusCode::BadRequest => buf.write(b"400 Bad Request"),
+            StatusCode::RequestTimeout => buf.write(b"408 Request Timeout"),
             StatusCode::Error => buf.write(b"500 Internal Server Error"),
         });
 
@@ -36,6 +60,7 @@ impl StatusCode {
 struct Response {
     code: StatusCode,
     body: Vec<u8>,
+    keep_alive: bool,
 }
 
 impl Response {
@@ -43,13 +68,19 @@ impl Response {
         Response {
             code: StatusCode::Ok,
             body: vec![],
+            keep_alive: false,
         }
     }
 
     fn write(&self, buf: &mut BufWriter<&mut Vec<u8>>) -> Result<usize, std::io::Error> {
         try!(self.code.write(buf));
-        try!(write!(buf, "Content-Lenght: {}\r\n", self.body.len()));
+        try!(write!(buf, "Content-Length: {}\r\n", self.body.len()));
         try!(buf.write(b"Content-type: text/plain; charset=UTF-8\r\n"));
+        try!(buf.write(if self.keep_alive {
+            b"Connection: keep-alive\r\n"
+        } else {
+            b"Connection: close\r\n"
+        }));
         try!(buf.write(b"\r\n"));
         try!(buf.write(&self.body));
 
@@ -57,31 +88,541 @@ impl Response {
     }
 }
 
-#[derive(Debug)]
+#[derive(Debug, PartialEq)]
 enum State {
     Reading,
     Handling,
     Writing,
+    // The socket has been shut down and deregistered; the slab entry is
+    // only waiting to be reclaimed by the handler that observed this.
+    Closed,
+}
+
+// Stages of the incremental HTTP/1.1 parser, driven one `advance` call per
+// readable event so a request can arrive split across any number of reads.
+#[derive(Debug, PartialEq)]
+enum ParseStage {
+    RequestLine,
+    Headers,
+    Body,
+    Done,
+}
+
+// Sub-state used while `ParseStage::Body` is decoding a chunked transfer.
+#[derive(Debug)]
+enum ChunkStage {
+    Size,
+    Data(usize),
+    Trailer,
 }
 
+// Incremental request parser. Bytes are handed to `advance` as they arrive;
+// `cursor` remembers how far into the buffer we've already parsed so a
+// re-entrant call resumes instead of re-scanning from the start.
 #[derive(Debug)]
-struct RequestParser<'a>(&'a str);
+struct RequestParser {
+    stage: ParseStage,
+    cursor: usize,
+    method: String,
+    path: String,
+    version: String,
+    headers: Vec<(String, String)>,
+    body: Vec<u8>,
+    content_length: Option<usize>,
+    chunked: bool,
+    chunk_stage: ChunkStage,
+    params: Vec<(String, String)>,
+    expect_continue: bool,
+}
+
+impl RequestParser {
+    fn new() -> RequestParser {
+        RequestParser {
+            stage: ParseStage::RequestLine,
+            cursor: 0,
+            method: String::new(),
+            path: String::new(),
+            version: String::new(),
+            headers: vec![],
+            body: vec![],
+            content_length: None,
+            chunked: false,
+            chunk_stage: ChunkStage::Size,
+            params: vec![],
+            expect_continue: false,
+        }
+    }
+
+    fn method(&self) -> Option<&str> {
+        if self.stage == ParseStage::RequestLine {
+            None
+        } else {
+            Some(&self.method)
+        }
+    }
+
+    fn path(&self) -> Option<&str> {
+        if self.stage == ParseStage::RequestLine {
+            None
+        } else {
+            Some(&self.path)
+        }
+    }
+
+    fn version(&self) -> Option<&str> {
+        if self.stage == ParseStage::RequestLine {
+            None
+        } else {
+            Some(&self.version)
+        }
+    }
+
+    fn headers(&self) -> &Vec<(String, String)> {
+        &self.headers
+    }
+
+    fn header(&self, name: &str) -> Option<&str> {
+        self.headers
+            .iter()
+            .find(|&(ref n, _)| n.eq_ignore_ascii_case(name))
+            .map(|&(_, ref v)| v.as_str())
+    }
+
+    fn body(&self) -> &[u8] {
+        &self.body
+    }
+
+    // Looks up a `:param` captured from the matched route pattern, e.g.
+    // `parser.param("id")` for a route registered as `/users/:id`.
+    fn param(&self, name: &str) -> Option<&str> {
+        self.params
+            .iter()
+            .find(|&(ref n, _)| n == name)
+            .map(|&(_, ref v)| v.as_str())
+    }
 
-impl<'a> RequestParser<'a> {
-    fn method(&self) -> Option<&'a str> {
-        self.0.split_whitespace().next()
+    // Consumes the "client is waiting on a 100 Continue" flag set once
+    // the header block finishes, so the caller sends the interim
+    // response at most once per request.
+    fn take_expect_continue(&mut self) -> bool {
+        let expect_continue = self.expect_continue;
+        self.expect_continue = false;
+        expect_continue
     }
 
-    fn path(&self) -> Option<&'a str> {
-        self.0.split_whitespace().nth(1)
+    // Feeds the full buffer read so far and drives the state machine
+    // forward from `cursor`. Returns `Ok(true)` once the request (headers
+    // and, if present, body) is fully buffered, `Ok(false)` if more bytes
+    // are needed, or `Err(status)` on malformed input.
+    fn advance(&mut self, buf: &[u8]) -> Result<bool, StatusCode> {
+        loop {
+            if self.stage == ParseStage::RequestLine || self.stage == ParseStage::Headers {
+                // Header bytes seen so far: the lines already consumed plus
+                // the current line, or everything buffered if it's incomplete.
+                let header_bytes = match find_crlf(&buf[self.cursor..]) {
+                    Some(end) => self.cursor + end + 2,
+                    None => buf.len(),
+                };
+                if header_bytes > MAX_HEADER_BYTES {
+                    return Err(StatusCode::BadRequest);
+                }
+            }
+
+            match self.stage {
+                ParseStage::RequestLine => {
+                    match find_crlf(&buf[self.cursor..]) {
+                        Some(end) => {
+                            let line = try!(ascii(&buf[self.cursor..self.cursor + end]));
+                            try!(self.parse_request_line(line));
+                            self.cursor += end + 2;
+                            self.stage = ParseStage::Headers;
+                        }
+                        None => return Ok(false),
+                    }
+                }
+                ParseStage::Headers => {
+                    match find_crlf(&buf[self.cursor..]) {
+                        Some(0) => {
+                            self.cursor += 2;
+                            self.content_length = try!(self.parse_content_length());
+                            self.chunked = self.is_chunked();
+                            self.expect_continue = self.wants_continue();
+                            self.stage = ParseStage::Body;
+                        }
+                        Some(end) => {
+                            let line = try!(ascii(&buf[self.cursor..self.cursor + end]));
+                            try!(self.parse_header_line(line));
+                            self.cursor += end + 2;
+                        }
+                        None => return Ok(false),
+                    }
+                }
+                ParseStage::Body => {
+                    if self.chunked {
+                        if try!(self.advance_chunked(buf)) {
+                            self.stage = ParseStage::Done;
+                        } else {
+                            return Ok(false);
+                        }
+                    } else {
+                        let needed = self.content_length.unwrap_or(0);
+                        let available = buf.len() - self.cursor;
+                        if available >= needed {
+                            self.body = buf[self.cursor..self.cursor + needed].to_vec();
+                            self.cursor += needed;
+                            self.stage = ParseStage::Done;
+                        } else {
+                            return Ok(false);
+                        }
+                    }
+                }
+                ParseStage::Done => return Ok(true),
+            }
+        }
+    }
+
+    fn advance_chunked(&mut self, buf: &[u8]) -> Result<bool, StatusCode> {
+        loop {
+            match self.chunk_stage {
+                ChunkStage::Size => {
+                    match find_crlf(&buf[self.cursor..]) {
+                        Some(end) => {
+                            let line = try!(ascii(&buf[self.cursor..self.cursor + end]));
+                            let size_str = line.split(';').next().unwrap_or("").trim();
+                            let size = try!(usize::from_str_radix(size_str, 16)
+                                .map_err(|_| StatusCode::BadRequest));
+                            self.cursor += end + 2;
+                            self.chunk_stage = if size == 0 {
+                                ChunkStage::Trailer
+                            } else {
+                                ChunkStage::Data(size)
+                            };
+                        }
+                        None => return Ok(false),
+                    }
+                }
+                ChunkStage::Data(size) => {
+                    let available = buf.len() - self.cursor;
+                    if available >= size + 2 {
+                        self.body.extend_from_slice(&buf[self.cursor..self.cursor + size]);
+                        self.cursor += size + 2;
+                        self.chunk_stage = ChunkStage::Size;
+                    } else {
+                        return Ok(false);
+                    }
+                }
+                ChunkStage::Trailer => {
+                    match find_crlf(&buf[self.cursor..]) {
+                        Some(0) => {
+                            self.cursor += 2;
+                            return Ok(true);
+                        }
+                        Some(_) => return Err(StatusCode::BadRequest),
+                        None => return Ok(false),
+                    }
+                }
+            }
+        }
+    }
+
+    fn parse_request_line(&mut self, line: &str) -> Result<(), StatusCode> {
+        let mut parts = line.split_whitespace();
+        self.method = try!(parts.next().ok_or(StatusCode::BadRequest)).to_string();
+        self.path = try!(parts.next().ok_or(StatusCode::BadRequest)).to_string();
+        self.version = try!(parts.next().ok_or(StatusCode::BadRequest)).to_string();
+
+        Ok(())
+    }
+
+    fn parse_header_line(&mut self, line: &str) -> Result<(), StatusCode> {
+        let idx = try!(line.find(':').ok_or(StatusCode::BadRequest));
+        let (name, value) = line.split_at(idx);
+        self.headers.push((name.trim().to_string(), value[1..].trim().to_string()));
+
+        Ok(())
+    }
+
+    fn parse_content_length(&self) -> Result<Option<usize>, StatusCode> {
+        match self.header("Content-Length") {
+            Some(value) => {
+                value.trim()
+                    .parse()
+                    .map(Some)
+                    .map_err(|_| StatusCode::BadRequest)
+            }
+            None => Ok(None),
+        }
     }
 
-    fn headers(&self) -> Vec<&'a str> {
-        self.0.lines().skip(1).take_while(|line| *line != "").collect()
+    fn is_chunked(&self) -> bool {
+        self.header("Transfer-Encoding")
+            .map_or(false, |value| value.to_lowercase().contains("chunked"))
     }
 
-    fn header(&self, name: &str) -> Option<&'a str> {
-        self.0.lines().skip(1).take_while(|line| *line != "").filter(|line| line.starts_with(name)).next()
+    fn wants_continue(&self) -> bool {
+        self.header("Expect")
+            .map_or(false, |value| value.eq_ignore_ascii_case("100-continue"))
+    }
+}
+
+fn find_crlf(buf: &[u8]) -> Option<usize> {
+    buf.windows(2).position(|w| w == b"\r\n")
+}
+
+fn ascii(buf: &[u8]) -> Result<&str, StatusCode> {
+    std::str::from_utf8(buf).map_err(|_| StatusCode::BadRequest)
+}
+
+// Which wire protocol a connection has settled on. A fresh connection
+// starts `Unknown` until at least the preface's 24 bytes are buffered; it
+// then becomes `H2` if they match the HTTP/2 connection preface and `H1`
+// otherwise. An `Unknown` connection is parsed as HTTP/1.
+#[derive(Debug, PartialEq)]
+enum Protocol {
+    Unknown,
+    H1,
+    H2,
+}
+
+const H2_FLAG_END_STREAM: u8 = 0x1;
+const H2_FLAG_END_HEADERS: u8 = 0x4;
+const H2_FRAME_DATA: u8 = 0x0;
+const H2_FRAME_HEADERS: u8 = 0x1;
+const H2_FRAME_SETTINGS: u8 = 0x4;
+
+// Per-stream accumulation for a single h2 HEADERS (+ trailing DATA)
+// exchange. `headers` holds the raw, still HPACK-encoded header block;
+// this server does not implement HPACK, so handlers never decode it.
+#[derive(Debug)]
+struct H2Stream {
+    headers: Vec<u8>,
+    body: Vec<u8>,
+    end_stream: bool,
+}
+
+impl H2Stream {
+    fn new() -> H2Stream {
+        H2Stream {
+            headers: vec![],
+            body: vec![],
+            end_stream: false,
+        }
+    }
+}
+
+// Minimal HTTP/2 cleartext (h2c) framing: reads complete frames out of
+// the connection buffer (past the 24-byte preface) and routes HEADERS
+// and DATA payloads onto their stream id so several streams can be in
+// flight on one socket at once.
+#[derive(Debug)]
+struct Http2 {
+    cursor: usize,
+    streams: std::collections::HashMap<u32, H2Stream>,
+}
+
+impl Http2 {
+    fn new() -> Http2 {
+        Http2 {
+            cursor: HTTP2_PREFACE.len(),
+            streams: std::collections::HashMap::new(),
+        }
+    }
+
+    // Dispatches every complete frame currently buffered and returns the
+    // ids of streams that just saw END_STREAM, i.e. are ready to handle.
+    fn advance(&mut self, buf: &[u8]) -> Vec<u32> {
+        let mut ready = vec![];
+
+        while buf.len() - self.cursor >= 9 {
+            let header = &buf[self.cursor..self.cursor + 9];
+            let length = ((header[0] as usize) << 16) | ((header[1] as usize) << 8) |
+                         header[2] as usize;
+            let frame_type = header[3];
+            let flags = header[4];
+            let stream_id = (((header[5] as u32) << 24) | ((header[6] as u32) << 16) |
+                              ((header[7] as u32) << 8) | header[8] as u32) &
+                            0x7fffffff;
+
+            if buf.len() - self.cursor < 9 + length {
+                break;
+            }
+
+            let payload = &buf[self.cursor + 9..self.cursor + 9 + length];
+
+            match frame_type {
+                H2_FRAME_HEADERS => {
+                    let stream = self.streams.entry(stream_id).or_insert_with(H2Stream::new);
+                    stream.headers.extend_from_slice(payload);
+                    if flags & H2_FLAG_END_STREAM != 0 {
+                        stream.end_stream = true;
+                        ready.push(stream_id);
+                    }
+                }
+                H2_FRAME_DATA => {
+                    let stream = self.streams.entry(stream_id).or_insert_with(H2Stream::new);
+                    stream.body.extend_from_slice(payload);
+                    if flags & H2_FLAG_END_STREAM != 0 {
+                        stream.end_stream = true;
+                        ready.push(stream_id);
+                    }
+                }
+                H2_FRAME_SETTINGS => {
+                    // Nothing to negotiate yet; the preface reply already
+                    // sent our (empty) settings frame.
+                }
+                _ => {}
+            }
+
+            self.cursor += 9 + length;
+        }
+
+        ready
+    }
+}
+
+// Builds a 9-byte h2 frame header followed by `payload`.
+fn h2_frame(frame_type: u8, flags: u8, stream_id: u32, payload: &[u8]) -> Vec<u8> {
+    let length = payload.len();
+    let mut frame = Vec::with_capacity(9 + length);
+
+    frame.push((length >> 16) as u8);
+    frame.push((length >> 8) as u8);
+    frame.push(length as u8);
+    frame.push(frame_type);
+    frame.push(flags);
+    frame.push(((stream_id >> 24) & 0x7f) as u8);
+    frame.push((stream_id >> 16) as u8);
+    frame.push((stream_id >> 8) as u8);
+    frame.push(stream_id as u8);
+    frame.extend_from_slice(payload);
+
+    frame
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum Method {
+    Get,
+    Post,
+    Put,
+    Delete,
+    Head,
+    Options,
+    Patch,
+    Other,
+}
+
+impl Method {
+    fn parse(method: &str) -> Method {
+        match method {
+            "GET" => Method::Get,
+            "POST" => Method::Post,
+            "PUT" => Method::Put,
+            "DELETE" => Method::Delete,
+            "HEAD" => Method::Head,
+            "OPTIONS" => Method::Options,
+            "PATCH" => Method::Patch,
+            _ => Method::Other,
+        }
+    }
+}
+
+// One segment of a route pattern: a literal path component, or a
+// `:name` capture that's handed back to the handler via `parser.param`.
+enum Segment {
+    Exact(String),
+    Param(String),
+}
+
+fn parse_pattern(pattern: &str) -> Vec<Segment> {
+    pattern.trim_matches('/')
+        .split('/')
+        .map(|segment| {
+            if segment.starts_with(':') {
+                Segment::Param(segment[1..].to_string())
+            } else {
+                Segment::Exact(segment.to_string())
+            }
+        })
+        .collect()
+}
+
+// Matches a request path against a route's segments, returning the
+// captured `:param` values on success.
+fn match_segments(segments: &[Segment], path: &[&str]) -> Option<Vec<(String, String)>> {
+    if segments.len() != path.len() {
+        return None;
+    }
+
+    let mut params = vec![];
+
+    for (segment, value) in segments.iter().zip(path.iter()) {
+        match *segment {
+            Segment::Exact(ref expected) => {
+                if expected != value {
+                    return None;
+                }
+            }
+            Segment::Param(ref name) => {
+                params.push((name.clone(), value.to_string()));
+            }
+        }
+    }
+
+    Some(params)
+}
+
+type RouteHandler = Box<Fn(&RequestParser, &mut Response)>;
+
+struct Route {
+    method: Method,
+    segments: Vec<Segment>,
+    handler: RouteHandler,
+}
+
+// A table of `(Method, path pattern) -> handler` entries, checked in
+// registration order. Registered once at startup in `main`, then looked
+// up on every request instead of patching the dispatch match arm by
+// hand for each new endpoint.
+struct Router {
+    routes: Vec<Route>,
+}
+
+impl Router {
+    fn new() -> Router {
+        Router { routes: vec![] }
+    }
+
+    fn add<F>(&mut self, method: Method, pattern: &str, handler: F)
+        where F: Fn(&RequestParser, &mut Response) + 'static
+    {
+        self.routes.push(Route {
+            method: method,
+            segments: parse_pattern(pattern),
+            handler: Box::new(handler),
+        });
+    }
+
+    // Looks up the handler for `method`+`path`. If the path matches some
+    // route but under a different method, reports `MethodNotAllowed`
+    // rather than `NotFound` so clients can tell the two apart.
+    fn dispatch(&self, method: Method, path: &str) -> Result<(&RouteHandler, Vec<(String, String)>), StatusCode> {
+        let path_segments: Vec<&str> = path.trim_matches('/').split('/').collect();
+        let mut path_matched = false;
+
+        for route in &self.routes {
+            if let Some(params) = match_segments(&route.segments, &path_segments) {
+                if route.method == method {
+                    return Ok((&route.handler, params));
+                }
+                path_matched = true;
+            }
+        }
+
+        if path_matched {
+            Err(StatusCode::MethodNotAllowed)
+        } else {
+            Err(StatusCode::NotFound)
+        }
     }
 }
 
@@ -92,6 +633,17 @@ struct Request {
     state: State,
     req: Vec<u8>,
     res: Vec<u8>,
+    res_pos: usize,
+    // Out-of-band bytes that bypass the main `res`/`state` flow entirely
+    // (h2 control/response frames, the `100 Continue` interim status
+    // line) but still need the same writable-event, short-write-safe
+    // draining `res` gets in `write`.
+    out: Vec<u8>,
+    out_pos: usize,
+    parser: RequestParser,
+    keep_alive: bool,
+    protocol: Protocol,
+    h2: Http2,
 }
 
 impl Request {
@@ -102,22 +654,43 @@ impl Request {
             state: State::Reading,
             req: vec![],
             res: vec![],
+            res_pos: 0,
+            out: vec![],
+            out_pos: 0,
+            parser: RequestParser::new(),
+            keep_alive: false,
+            protocol: Protocol::Unknown,
+            h2: Http2::new(),
         }
     }
 
-    fn ready(&mut self, event_loop: &mut EventLoop<HttpServer>, events: EventSet) {
+    fn ready(&mut self, event_loop: &mut EventLoop<HttpServer>, events: EventSet, router: &Router) {
+        // Pending out-of-band bytes can be flushed on any writable
+        // event regardless of `state`, since `reregister` adds writable
+        // interest for them alongside whatever the state itself needs;
+        // drain them first so a `Reading`-state connection with queued
+        // bytes (e.g. the h2 SETTINGS reply) doesn't lose them.
+        if events.is_writable() {
+            self.drain_out(event_loop);
+        }
+
         match self.state {
             State::Reading => {
-                assert!(events.is_readable(), "not readable; {:?}", events);
-                self.read(event_loop);
+                if events.is_readable() {
+                    self.read(event_loop);
+                } else {
+                    self.reregister(event_loop);
+                }
             }
             State::Handling => {
-                self.handle(event_loop);
+                self.handle(event_loop, router);
             }
             State::Writing => {
-                assert!(events.is_writable(), "not writable; {:?}", events);
-                self.write(event_loop);
+                if events.is_writable() {
+                    self.write(event_loop);
+                }
             }
+            State::Closed => {}
         }
     }
 
@@ -129,10 +702,46 @@ impl Request {
             }
             Ok(Some(n)) => {
                 println!("read {} bytes", n);
-                if self.req.ends_with(b"\r\n\r\n") {
-                    println!("found end!");
-                    self.state = State::Handling;
+
+                if self.protocol == Protocol::Unknown && self.req.len() >= HTTP2_PREFACE.len() {
+                    if &self.req[..HTTP2_PREFACE.len()] == HTTP2_PREFACE {
+                        println!("http/2 preface detected");
+                        self.protocol = Protocol::H2;
+                        self.send_h2_preface_reply();
+                    } else {
+                        self.protocol = Protocol::H1;
+                    }
+                }
+
+                match self.protocol {
+                    Protocol::H2 => {
+                        let ready = self.h2.advance(&self.req);
+                        if !ready.is_empty() {
+                            println!("h2 streams ready: {:?}", ready);
+                            self.state = State::Handling;
+                        }
+                    }
+                    Protocol::H1 | Protocol::Unknown => {
+                        let result = self.parser.advance(&self.req);
+
+                        if self.parser.take_expect_continue() {
+                            self.send_100_continue();
+                        }
+
+                        match result {
+                            Ok(true) => {
+                                println!("found end!");
+                                self.state = State::Handling;
+                            }
+                            Ok(false) => {}
+                            Err(code) => {
+                                println!("bad request: {:?}", code);
+                                self.fail(code);
+                            }
+                        }
+                    }
                 }
+
                 self.reregister(event_loop);
             }
             Ok(None) => {
@@ -143,42 +752,141 @@ impl Request {
                 panic!("read error! {:?}", e);
             }
         }
-        // println!("=====");
-        // match String::from_utf8(self.buf.clone()) {
-        //     Ok(s) => println!("{:?}", s),
-        //     Err(e) => println!("err! {}", e),
-        // };
-        // println!("=====");
     }
 
-    fn handle(&mut self, event_loop: &mut EventLoop<HttpServer>) {
-        let mut response = Response::new();
+    // Replies to the h2c preface with our own (empty) SETTINGS frame, per
+    // the HTTP/2 handshake. Queued onto `out` rather than written
+    // straight to the socket: the stream is non-blocking, so a short
+    // write here would silently corrupt the frame.
+    fn send_h2_preface_reply(&mut self) {
+        let settings = h2_frame(H2_FRAME_SETTINGS, 0, 0, &[]);
+        self.queue_out(&settings);
+    }
+
+    // Appends out-of-band bytes (h2 control/response frames, the
+    // 100-continue line) to `out`. The caller's own `reregister` call
+    // (always made before the event loop yields control back) picks up
+    // the pending bytes and adds writable interest; `drain_out` flushes
+    // them on the next writable event, looping over short writes the
+    // same way `write` does for `res`.
+    fn queue_out(&mut self, bytes: &[u8]) {
+        self.out.extend_from_slice(bytes);
+    }
 
-        match std::str::from_utf8(&self.req) {
-            Ok(req) => {
-                let mut buf = BufWriter::new(&mut response.body);
+    // Flushes as much of `out` as the socket will currently accept,
+    // advancing `out_pos` so a short write or `WouldBlock` just waits
+    // for the next writable event instead of dropping bytes.
+    fn drain_out(&mut self, event_loop: &mut EventLoop<HttpServer>) {
+        if self.out_pos >= self.out.len() {
+            return;
+        }
 
-                let parser = RequestParser(req);
-                println!("method: {:?}", parser.method());
-                println!("path: {:?}", parser.path());
-                println!("headers: {:?}", parser.headers());
+        let remaining = &self.out[self.out_pos..];
+        let cur = Cursor::new(remaining);
+        let mut tak = Take::new(cur, remaining.len());
 
-                match parser.path() {
-                    Some("/") => {
-                        buf.write(b"Hello World!\n").unwrap();
-                        buf.write(format!("{:?}", std::time::SystemTime::now()).as_bytes()).unwrap();
-                        buf.write(format!("{:?}", parser.header("User-Agent")).as_bytes()).unwrap();
-                    }
-                    Some("/other") => {
-                        buf.write(b"This is the other path!").unwrap();
-                    }
-                    _ => {
-                        response.code = StatusCode::NotFound;
-                    }
+        match self.stream.try_write_buf(&mut tak) {
+            Ok(Some(n)) => {
+                self.out_pos += n;
+                if self.out_pos == self.out.len() {
+                    self.out.clear();
+                    self.out_pos = 0;
+                } else {
+                    self.reregister(event_loop);
                 }
             }
-            Err(_) => {
-                response.code = StatusCode::Error;
+            Ok(None) => self.reregister(event_loop),
+            Err(e) => {
+                println!("could not flush out-of-band write! {}", e);
+            }
+        }
+    }
+
+    // Queues the `100 Continue` interim status line when the client sent
+    // `Expect: 100-continue`, so it starts streaming the body without
+    // waiting on the final response. This bypasses `self.res` and
+    // `state` entirely; the real response is still assembled and
+    // written once the body finishes parsing. Queued onto `out` rather
+    // than written straight to the socket, since the stream is
+    // non-blocking and a short write here would otherwise be dropped.
+    fn send_100_continue(&mut self) {
+        self.queue_out(b"HTTP/1.1 100 Continue\r\n\r\n");
+    }
+
+    // Short-circuits straight to `State::Writing` with the given status,
+    // skipping `handle` entirely (used for malformed requests). The
+    // connection is never kept alive after a malformed request since we
+    // can no longer trust where the next request would start.
+    fn fail(&mut self, code: StatusCode) {
+        let mut response = Response::new();
+        response.code = code;
+        response.keep_alive = false;
+        self.keep_alive = false;
+
+        {
+            let mut buf = BufWriter::new(&mut self.res);
+            response.write(&mut buf).unwrap();
+        }
+
+        self.state = State::Writing;
+    }
+
+    // Fired when the request's idle/slow-request timer expires. Only
+    // acts while still `State::Reading`; the timer is cancelled on
+    // completion, so a firing that races a just-finished read is
+    // harmless here. `fail` writes an HTTP/1.1 status line, which would
+    // corrupt an h2 connection's framing, so an idle h2 connection is
+    // just closed instead of getting a 408.
+    fn on_timeout(&mut self, event_loop: &mut EventLoop<HttpServer>) {
+        if let State::Reading = self.state {
+            if self.protocol == Protocol::H2 {
+                println!("h2 connection idle, closing");
+                self.close(event_loop);
+            } else {
+                println!("request timed out");
+                self.fail(StatusCode::RequestTimeout);
+                self.reregister(event_loop);
+            }
+        }
+    }
+
+    // Decides whether the connection should be kept open after this
+    // response, per the request's `Connection` header and HTTP version
+    // (1.1 defaults to keep-alive, 1.0 defaults to close).
+    fn wants_keep_alive(&self) -> bool {
+        match self.parser.header("Connection") {
+            Some(value) if value.eq_ignore_ascii_case("close") => false,
+            Some(value) if value.eq_ignore_ascii_case("keep-alive") => true,
+            _ => self.parser.version() == Some("HTTP/1.1"),
+        }
+    }
+
+    fn handle(&mut self, event_loop: &mut EventLoop<HttpServer>, router: &Router) {
+        match self.protocol {
+            Protocol::H2 => self.handle_h2(event_loop),
+            Protocol::H1 | Protocol::Unknown => self.handle_h1(event_loop, router),
+        }
+    }
+
+    fn handle_h1(&mut self, event_loop: &mut EventLoop<HttpServer>, router: &Router) {
+        let mut response = Response::new();
+        response.keep_alive = self.wants_keep_alive();
+        self.keep_alive = response.keep_alive;
+
+        println!("method: {:?}", self.parser.method());
+        println!("path: {:?}", self.parser.path());
+        println!("headers: {:?}", self.parser.headers());
+
+        let method = Method::parse(self.parser.method().unwrap_or(""));
+        let path = self.parser.path().unwrap_or("").to_string();
+
+        match router.dispatch(method, &path) {
+            Ok((handler, params)) => {
+                self.parser.params = params;
+                handler(&self.parser, &mut response);
+            }
+            Err(code) => {
+                response.code = code;
             }
         }
 
@@ -191,15 +899,62 @@ impl Request {
         self.reregister(event_loop);
     }
 
+    // Answers every h2 stream that has seen END_STREAM, then goes back to
+    // reading so further streams on the same connection can be served.
+    fn handle_h2(&mut self, event_loop: &mut EventLoop<HttpServer>) {
+        let ready: Vec<u32> = self.h2
+            .streams
+            .iter()
+            .filter(|&(_, stream)| stream.end_stream)
+            .map(|(&id, _)| id)
+            .collect();
+
+        for id in ready {
+            println!("handling h2 stream {}", id);
+            self.write_h2_response(id);
+            self.h2.streams.remove(&id);
+        }
+
+        self.state = State::Reading;
+        self.reregister(event_loop);
+    }
+
+    // Minimal h2 response: this server doesn't implement general HPACK
+    // encoding, so the header block is just the single static-table
+    // indexed field (0x88 == indexed header field, index 8) that HPACK's
+    // static table (RFC 7541 Appendix A) defines as `:status: 200` —
+    // enough for a real h2 client to accept the response — flagged
+    // END_HEADERS, followed by a DATA frame carrying a plaintext body and
+    // flagged END_STREAM.
+    fn write_h2_response(&mut self, stream_id: u32) {
+        let body: &[u8] = b"Hello HTTP/2!\n";
+        let status_200: &[u8] = &[0x88];
+        let headers_frame = h2_frame(H2_FRAME_HEADERS,
+                                      H2_FLAG_END_HEADERS,
+                                      stream_id,
+                                      status_200);
+        let data_frame = h2_frame(H2_FRAME_DATA, H2_FLAG_END_STREAM, stream_id, body);
+
+        self.queue_out(&headers_frame);
+        self.queue_out(&data_frame);
+    }
+
     fn write(&mut self, event_loop: &mut EventLoop<HttpServer>) {
-        let len = self.res.len();
+        let remaining = &self.res[self.res_pos..];
 
-        println!("bytes {}", &len);
-        let cur = Cursor::new(self.res.as_slice());
-        let mut tak = Take::new(cur, len);
+        println!("bytes remaining {}", remaining.len());
+        let cur = Cursor::new(remaining);
+        let mut tak = Take::new(cur, remaining.len());
         match self.stream.try_write_buf(&mut tak) {
             Ok(Some(n)) => {
                 println!("wrote {} bytes", n);
+                self.res_pos += n;
+                if self.res_pos == self.res.len() {
+                    self.finish(event_loop);
+                } else {
+                    println!("short write, {} bytes left", self.res.len() - self.res_pos);
+                    self.reregister(event_loop);
+                }
             }
             Ok(None) => {
                 println!("wrote nothing");
@@ -209,12 +964,46 @@ impl Request {
                 panic!("write error! {:?}", e);
             }
         }
+    }
 
-        println!("shutting down!");
+    // Either tears down the socket or, for a keep-alive response, resets
+    // the request to a clean slate and goes back to waiting for the next
+    // request on the same connection instead of dropping the slab entry.
+    fn finish(&mut self, event_loop: &mut EventLoop<HttpServer>) {
+        if self.keep_alive {
+            println!("keeping connection alive");
+            self.recycle();
+            self.reregister(event_loop);
+        } else {
+            println!("shutting down!");
+            self.close(event_loop);
+        }
+    }
 
+    // Shuts down and deregisters the socket and marks the request
+    // `Closed`. The caller (`HttpServer`) is responsible for noticing
+    // `Closed` and reclaiming the slab entry/token, since only it holds
+    // the slab.
+    fn close(&mut self, event_loop: &mut EventLoop<HttpServer>) {
         self.stream.shutdown(Shutdown::Both).unwrap_or_else(|e| {
             println!("could not shut down stream! {}", e);
         });
+
+        event_loop.deregister(&self.stream).unwrap_or_else(|e| {
+            println!("could not deregister stream! {}", e);
+        });
+
+        self.state = State::Closed;
+    }
+
+    fn recycle(&mut self) {
+        self.req.clear();
+        self.res.clear();
+        self.res_pos = 0;
+        self.out.clear();
+        self.out_pos = 0;
+        self.parser = RequestParser::new();
+        self.state = State::Reading;
     }
 
     fn reregister(&self, event_loop: &mut EventLoop<HttpServer>) {
@@ -223,31 +1012,78 @@ impl Request {
             State::Reading => EventSet::readable(),
             State::Handling => EventSet::all(),
             State::Writing => EventSet::writable(),
+            State::Closed => return,
+        };
+
+        let event_set = if self.out_pos < self.out.len() {
+            event_set | EventSet::writable()
+        } else {
+            event_set
         };
 
         event_loop.reregister(&self.stream, self.token, event_set, PollOpt::oneshot()).unwrap()
     }
 }
 
-#[derive(Debug)]
 struct HttpServer {
     listener: TcpListener,
     requests: Slab<Request>,
+    timeouts: HashMap<Token, Timeout>,
+    router: Router,
 }
 
 impl HttpServer {
-    fn new(listener: TcpListener) -> HttpServer {
+    fn new(listener: TcpListener, router: Router) -> HttpServer {
         let slab = Slab::new_starting_at(Token(1), 1024);
 
         HttpServer {
             listener: listener,
             requests: slab,
+            timeouts: HashMap::new(),
+            router: router,
+        }
+    }
+
+    // Cancels any outstanding idle/slow-request timer for `token`.
+    fn clear_timeout(&mut self, event_loop: &mut EventLoop<HttpServer>, token: Token) {
+        if let Some(timeout) = self.timeouts.remove(&token) {
+            event_loop.clear_timeout(timeout);
+        }
+    }
+
+    // Cancels and reschedules the idle/slow-request timer for `token`,
+    // called whenever the request makes progress while still reading.
+    fn schedule_timeout(&mut self, event_loop: &mut EventLoop<HttpServer>, token: Token) {
+        self.clear_timeout(event_loop, token);
+
+        match event_loop.timeout_ms(token, REQUEST_TIMEOUT_MS) {
+            Ok(timeout) => {
+                self.timeouts.insert(token, timeout);
+            }
+            Err(e) => {
+                println!("could not schedule timeout! {:?}", e);
+            }
+        }
+    }
+
+    // Reacts to a request's state after it ran: reschedules the
+    // idle/slow-request timer while it's still reading, or reclaims the
+    // slab entry once it's `Closed` so the token (and the memory behind
+    // it) is available to a future connection instead of leaking.
+    fn react(&mut self, event_loop: &mut EventLoop<HttpServer>, token: Token) {
+        match self.requests[token].state {
+            State::Reading => self.schedule_timeout(event_loop, token),
+            State::Closed => {
+                self.clear_timeout(event_loop, token);
+                self.requests.remove(token);
+            }
+            _ => self.clear_timeout(event_loop, token),
         }
     }
 }
 
 impl Handler for HttpServer {
-    type Timeout = ();
+    type Timeout = Token;
     type Message = ();
 
     fn ready(&mut self, event_loop: &mut EventLoop<Self>, token: Token, events: EventSet) {
@@ -265,7 +1101,9 @@ impl Handler for HttpServer {
                                       token,
                                       EventSet::readable(),
                                       PollOpt::edge() | PollOpt::oneshot())
-                            .unwrap()
+                            .unwrap();
+
+                        self.schedule_timeout(event_loop, token);
                     }
                     Ok(None) => {
                         println!("false alarm!");
@@ -276,10 +1114,37 @@ impl Handler for HttpServer {
                 }
             }
             _ => {
-                self.requests[token].ready(event_loop, events);
+                let router = &self.router;
+                self.requests[token].ready(event_loop, events, router);
+                self.react(event_loop, token);
             }
         }
     }
+
+    fn timeout(&mut self, event_loop: &mut EventLoop<Self>, token: Token) {
+        self.timeouts.remove(&token);
+
+        if self.requests.contains(token) {
+            self.requests[token].on_timeout(event_loop);
+            self.react(event_loop, token);
+        }
+    }
+}
+
+fn routes() -> Router {
+    let mut router = Router::new();
+
+    router.add(Method::Get, "/", |parser, response| {
+        response.body.write(b"Hello World!\n").unwrap();
+        response.body.write(format!("{:?}", std::time::SystemTime::now()).as_bytes()).unwrap();
+        response.body.write(format!("{:?}", parser.header("User-Agent")).as_bytes()).unwrap();
+    });
+
+    router.add(Method::Get, "/other", |_parser, response| {
+        response.body.write(b"This is the other path!").unwrap();
+    });
+
+    router
 }
 
 fn main() {
@@ -292,6 +1157,6 @@ fn main() {
     event_loop.register(&listener, SERVER, EventSet::readable(), PollOpt::edge())
         .unwrap();
 
-    let mut http_server = HttpServer::new(listener);
+    let mut http_server = HttpServer::new(listener, routes());
     event_loop.run(&mut http_server).unwrap();
 }